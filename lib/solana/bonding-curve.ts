@@ -17,6 +17,11 @@ export const BONDING_CURVE_PROGRAM_ID = new PublicKey(
 export const VAULT_SEED = 'vault';
 export const CURVE_SEED = 'curve';
 
+// u64 instruction arguments are fixed-width little-endian
+function encodeU64(value: number | string): Buffer {
+  return new BN(value).toArrayLike(Buffer, 'le', 8);
+}
+
 export interface BondingCurveConfig {
   basePrice: number;
   reserveRatio: number;
@@ -73,11 +78,11 @@ export function createInitializeBondingCurveInstruction(
 
   const data = Buffer.alloc(1 + 8 + 8 + 8 + 8 + 8);
   data.writeUInt8(0, 0); // Instruction index: 0 = initialize
-  new BN(params.initialSupply.toString()).toBuffer().copy(data, 1);
-  new BN(Math.floor(params.basePrice * 1e9)).toBuffer().copy(data, 9);
-  new BN(Math.floor(params.reserveRatio * 1e9)).toBuffer().copy(data, 17);
-  new BN(Math.floor(params.slope * 1e9)).toBuffer().copy(data, 25);
-  new BN(Math.floor(params.supplyMultiplier * 1e9)).toBuffer().copy(data, 33);
+  encodeU64(params.initialSupply.toString()).copy(data, 1);
+  encodeU64(Math.floor(params.basePrice * 1e9)).copy(data, 9);
+  encodeU64(Math.floor(params.reserveRatio * 1e9)).copy(data, 17);
+  encodeU64(Math.floor(params.slope * 1e9)).copy(data, 25);
+  encodeU64(Math.floor(params.supplyMultiplier * 1e9)).copy(data, 33);
 
   return new TransactionInstruction({
     keys,
@@ -117,8 +122,8 @@ export function createSwapTokensInstruction(
 
   const data = Buffer.alloc(1 + 8 + 8 + 1);
   data.writeUInt8(1, 0); // Instruction index: 1 = swap
-  new BN(params.amount.toString()).toBuffer().copy(data, 1);
-  new BN(params.minOutput.toString()).toBuffer().copy(data, 9);
+  encodeU64(params.amount.toString()).copy(data, 1);
+  encodeU64(params.minOutput.toString()).copy(data, 9);
   data.writeUInt8(params.isBuy ? 1 : 0, 17); // 1 for buy, 0 for sell
 
   return new TransactionInstruction({
@@ -1,6 +1,20 @@
-import { Transaction, Keypair, Connection, SendTransactionError, PublicKey, TransactionInstruction } from "@solana/web3.js"
+import {
+  Transaction,
+  Keypair,
+  Connection,
+  SendTransactionError,
+  PublicKey,
+  TransactionInstruction,
+  SystemProgram,
+  SystemInstruction,
+  ComputeBudgetProgram,
+  ComputeBudgetInstruction,
+  NONCE_ACCOUNT_LENGTH,
+} from "@solana/web3.js"
+import bs58 from "bs58"
 import { logger } from "../logger"
 import { sleep } from "../utils"
+import { createSwapTokensInstruction, SwapTokensParams } from "./bonding-curve"
 
 const MAX_RETRIES = 3
 const CONFIRMATION_TIMEOUT = 60000 // 60 seconds
@@ -8,6 +22,8 @@ const MAX_TRANSACTION_SIZE = 1232 // Maximum size in bytes
 const MAX_INSTRUCTIONS = 20 // Maximum number of instructions per transaction
 const RATE_LIMIT_WINDOW = 60000 // 1 minute window
 const MAX_TRANSACTIONS_PER_WINDOW = 10 // Maximum transactions per window
+const MAX_COMPUTE_UNIT_LIMIT = 1_400_000 // Runtime maximum per transaction
+const COMPUTE_UNIT_HEADROOM = 1.1 // 10% above simulated usage
 
 // Rate limiting map: wallet public key -> timestamps of recent transactions
 const transactionRateLimit = new Map<string, number[]>()
@@ -24,8 +40,8 @@ function validateTransaction(transaction: Transaction): void {
     throw new Error("Invalid transaction: Transaction cannot be null")
   }
 
-  // Check transaction size
-  const rawTransaction = transaction.serialize()
+  // Check transaction size (signatures are checked separately; missing ones still take their slot)
+  const rawTransaction = transaction.serialize({ requireAllSignatures: false, verifySignatures: false })
   if (rawTransaction.length > MAX_TRANSACTION_SIZE) {
     throw new Error(`Transaction too large: ${rawTransaction.length} bytes`)
   }
@@ -43,6 +59,53 @@ function validateTransaction(transaction: Transaction): void {
   })
 }
 
+// Matches the runtime's rule: a durable nonce transaction starts with AdvanceNonceAccount.
+// nonceInfo is not restored by Transaction.from, so it can't be relied on here.
+function isDurableNonceTransaction(transaction: Transaction): boolean {
+  const [first] = transaction.instructions
+  if (!first || !first.programId.equals(SystemProgram.programId)) {
+    return false
+  }
+
+  try {
+    return SystemInstruction.decodeInstructionType(first) === "AdvanceNonceAccount"
+  } catch {
+    return false
+  }
+}
+
+// Signing a transaction that never expires is an explicit opt-in: the caller names the
+// nonce account and authority it expects, and anything else is rejected
+function assertDurableNonce(
+  transaction: Transaction,
+  { nonceAccount, nonceAuthority }: DurableNonceConfig
+): void {
+  if (!isDurableNonceTransaction(transaction)) {
+    throw new Error("Transaction does not start with a nonce advance instruction")
+  }
+
+  const { noncePubkey, authorizedPubkey } = SystemInstruction.decodeNonceAdvance(transaction.instructions[0])
+  if (!noncePubkey.equals(nonceAccount) || !authorizedPubkey.equals(nonceAuthority)) {
+    throw new Error("Nonce account or authority mismatch")
+  }
+
+  if (!transaction.feePayer || !transaction.recentBlockhash) {
+    throw new Error("Durable nonce transaction is missing fee payer or nonce")
+  }
+}
+
+function isSetComputeUnitLimit(instruction: TransactionInstruction): boolean {
+  if (!instruction.programId.equals(ComputeBudgetProgram.programId)) {
+    return false
+  }
+
+  try {
+    return ComputeBudgetInstruction.decodeInstructionType(instruction) === "SetComputeUnitLimit"
+  } catch {
+    return false
+  }
+}
+
 function enforceRateLimit(walletPublicKey: string): void {
   const now = Date.now()
   const windowStart = now - RATE_LIMIT_WINDOW
@@ -63,7 +126,11 @@ function enforceRateLimit(walletPublicKey: string): void {
   transactionRateLimit.set(walletPublicKey, timestamps)
 }
 
-export async function signTransaction(transaction: Transaction, privateKey: Uint8Array): Promise<Transaction> {
+export async function signTransaction(
+  transaction: Transaction,
+  privateKey: Uint8Array,
+  nonceConfig?: DurableNonceConfig
+): Promise<Transaction> {
   try {
     // Validate inputs
     if (!privateKey || privateKey.length !== 64) {
@@ -72,29 +139,48 @@ export async function signTransaction(transaction: Transaction, privateKey: Uint
     
     validateTransaction(transaction)
 
-    const connection = new Connection(process.env.RPC_URL as string, "confirmed")
     const signer = Keypair.fromSecretKey(privateKey)
-    
-    // Verify the transaction hasn't been tampered with
-    if (transaction.feePayer && !transaction.feePayer.equals(signer.publicKey)) {
-      throw new Error("Transaction fee payer mismatch")
-    }
-    
-    transaction.feePayer = signer.publicKey
-    
-    const { blockhash } = await connection.getLatestBlockhash()
-    transaction.recentBlockhash = blockhash
-    
-    // Sign and verify signature
-    transaction.sign(signer)
-    if (!transaction.verifySignatures()) {
-      throw new Error("Transaction signature verification failed")
+
+    if (nonceConfig) {
+      assertDurableNonce(transaction, nonceConfig)
+
+      // The fee payer and nonce authority may be different keys signing separately,
+      // so keep the nonce, fee payer and any existing signatures intact
+      if (
+        !signer.publicKey.equals(transaction.feePayer as PublicKey) &&
+        !signer.publicKey.equals(nonceConfig.nonceAuthority)
+      ) {
+        throw new Error("Signer is neither the fee payer nor the nonce authority")
+      }
+
+      transaction.partialSign(signer)
+      if (!transaction.verifySignatures(false)) {
+        throw new Error("Transaction signature verification failed")
+      }
+    } else {
+      // Verify the transaction hasn't been tampered with
+      if (transaction.feePayer && !transaction.feePayer.equals(signer.publicKey)) {
+        throw new Error("Transaction fee payer mismatch")
+      }
+
+      transaction.feePayer = signer.publicKey
+
+      const connection = new Connection(process.env.RPC_URL as string, "confirmed")
+      const { blockhash } = await connection.getLatestBlockhash()
+      transaction.recentBlockhash = blockhash
+
+      // Sign and verify signature
+      transaction.sign(signer)
+      if (!transaction.verifySignatures()) {
+        throw new Error("Transaction signature verification failed")
+      }
     }
 
     logger.info("Transaction signed successfully", {
       signer: signer.publicKey.toString(),
+      isDurableNonce: !!nonceConfig,
       numInstructions: transaction.instructions.length,
-      transactionSize: transaction.serialize().length,
+      transactionSize: transaction.serialize({ requireAllSignatures: false }).length,
     })
 
     return transaction
@@ -104,12 +190,140 @@ export async function signTransaction(transaction: Transaction, privateKey: Uint
   }
 }
 
+// Sets the compute unit limit from a simulation of the instructions. Any SetComputeUnitLimit
+// already in the list is replaced, since the runtime rejects duplicates.
+export async function withSimulatedComputeUnitLimit(
+  connection: Connection,
+  feePayer: PublicKey,
+  instructions: TransactionInstruction[]
+): Promise<TransactionInstruction[]> {
+  const otherInstructions = instructions.filter(instruction => !isSetComputeUnitLimit(instruction))
+
+  // Simulate at the maximum so the estimate isn't capped by the default limit.
+  // simulateTransaction supplies its own blockhash for unsigned transactions.
+  const transaction = new Transaction()
+  transaction.feePayer = feePayer
+  transaction.add(
+    ComputeBudgetProgram.setComputeUnitLimit({ units: MAX_COMPUTE_UNIT_LIMIT }),
+    ...otherInstructions
+  )
+
+  const simulation = await connection.simulateTransaction(transaction)
+  if (simulation.value.err) {
+    throw new Error(`Compute unit simulation failed: ${JSON.stringify(simulation.value.err)}`)
+  }
+  if (!simulation.value.unitsConsumed) {
+    throw new Error("Compute unit simulation did not report units consumed")
+  }
+
+  const units = Math.min(
+    MAX_COMPUTE_UNIT_LIMIT,
+    Math.ceil(simulation.value.unitsConsumed * COMPUTE_UNIT_HEADROOM)
+  )
+
+  logger.info("Compute unit limit estimated", {
+    unitsConsumed: simulation.value.unitsConsumed,
+    units,
+  })
+
+  return [ComputeBudgetProgram.setComputeUnitLimit({ units }), ...otherInstructions]
+}
+
+export interface DurableNonceConfig {
+  nonceAccount: PublicKey
+  nonceAuthority: PublicKey
+}
+
+// Creates and initializes a nonce account. Signed online by the payer and the new nonce
+// account's keypair (pass it as an additional signer to signAndSendTransaction).
+export async function createNonceAccountTransaction(
+  connection: Connection,
+  payer: PublicKey,
+  nonceAccount: PublicKey,
+  nonceAuthority: PublicKey
+): Promise<Transaction> {
+  const lamports = await connection.getMinimumBalanceForRentExemption(NONCE_ACCOUNT_LENGTH)
+  const { blockhash, lastValidBlockHeight } = await connection.getLatestBlockhash('confirmed')
+
+  const transaction = SystemProgram.createNonceAccount({
+    fromPubkey: payer,
+    noncePubkey: nonceAccount,
+    authorizedPubkey: nonceAuthority,
+    lamports,
+  })
+  transaction.feePayer = payer
+  transaction.recentBlockhash = blockhash
+  transaction.lastValidBlockHeight = lastValidBlockHeight
+
+  return transaction
+}
+
+// Builds a transaction (e.g. a swap or withdraw) that uses the stored nonce instead of a
+// recent blockhash, so it can be signed offline without racing blockhash expiry.
+// Signers must pass the same DurableNonceConfig to signTransaction/signAndSendTransaction.
+export async function createDurableNonceTransaction(
+  connection: Connection,
+  feePayer: PublicKey,
+  instructions: TransactionInstruction[],
+  { nonceAccount, nonceAuthority }: DurableNonceConfig
+): Promise<Transaction> {
+  const nonce = await connection.getNonce(nonceAccount, "confirmed")
+  if (!nonce) {
+    throw new Error(`Nonce account not found: ${nonceAccount.toString()}`)
+  }
+  if (!nonce.authorizedPubkey.equals(nonceAuthority)) {
+    throw new Error("Nonce authority mismatch")
+  }
+
+  // The advance instruction must be the first instruction in the transaction
+  const nonceInstruction = SystemProgram.nonceAdvance({
+    noncePubkey: nonceAccount,
+    authorizedPubkey: nonceAuthority,
+  })
+
+  const transaction = new Transaction()
+  transaction.feePayer = feePayer
+  transaction.nonceInfo = { nonce: nonce.nonce, nonceInstruction }
+  transaction.recentBlockhash = nonce.nonce
+  transaction.add(nonceInstruction, ...instructions)
+
+  logger.info("Durable nonce transaction created", {
+    nonceAccount: nonceAccount.toString(),
+    numInstructions: transaction.instructions.length,
+  })
+
+  return transaction
+}
+
+// Builds a bonding curve swap that can be signed offline
+export async function createDurableNonceSwapTransaction(
+  connection: Connection,
+  feePayer: PublicKey,
+  params: SwapTokensParams,
+  nonceConfig: DurableNonceConfig
+): Promise<Transaction> {
+  return createDurableNonceTransaction(
+    connection,
+    feePayer,
+    [createSwapTokensInstruction(params)],
+    nonceConfig
+  )
+}
+
+// Serializes a partially signed transaction so it can be passed to an offline signer
+export function serializeForOfflineSigning(transaction: Transaction): string {
+  return transaction
+    .serialize({ requireAllSignatures: false, verifySignatures: false })
+    .toString("base64")
+}
+
 export async function signAndSendTransaction(
   connection: Connection,
   transaction: Transaction,
   wallet: PhantomWallet,
   additionalSigners: Keypair[] = [],
-  skipPreflight: boolean = false
+  skipPreflight: boolean = false,
+  nonceConfig?: DurableNonceConfig
 ): Promise<{ signature: string; result: any }> {
   let lastError: Error | undefined
   
@@ -122,6 +336,10 @@ export async function signAndSendTransaction(
     // Validate transaction before processing
     validateTransaction(transaction)
     
+    if (nonceConfig) {
+      assertDurableNonce(transaction, nonceConfig)
+    }
+
     // Enforce rate limiting
     enforceRateLimit(wallet.publicKey.toString())
 
@@ -129,6 +347,10 @@ export async function signAndSendTransaction(
     logger.error("Pre-transaction validation failed", error as Error)
     throw error
   }
+
+  if (nonceConfig) {
+    return sendDurableNonceTransaction(connection, transaction, wallet, additionalSigners, skipPreflight)
+  }
   
   for (let attempt = 0; attempt < MAX_RETRIES; attempt++) {
     try {
@@ -157,18 +379,19 @@ export async function signAndSendTransaction(
         // Manual signing flow for complex transactions
         signedTransaction = await wallet.signTransaction(transaction)
         
-        // Verify signature before proceeding
-        if (!signedTransaction.verifySignatures()) {
+        // Verify signature before proceeding; additional signers haven't signed yet
+        if (!signedTransaction.verifySignatures(false)) {
           throw new Error("Primary signature verification failed")
         }
         
         // Sign with any additional signers
         if (additionalSigners.length > 0) {
           signedTransaction.partialSign(...additionalSigners)
-          // Verify all signatures after additional signing
-          if (!signedTransaction.verifySignatures()) {
-            throw new Error("Additional signatures verification failed")
-          }
+        }
+
+        // Verify all required signatures are present
+        if (!signedTransaction.verifySignatures()) {
+          throw new Error("Transaction signature verification failed")
         }
 
         // Final transaction validation before sending
@@ -209,6 +432,98 @@ export async function signAndSendTransaction(
   throw lastError || new Error('Transaction failed after all retries')
 }
 
+// Nonce transactions are signed once and never re-signed: the nonce is the blockhash,
+// and once a send lands the nonce has advanced.
+async function sendDurableNonceTransaction(
+  connection: Connection,
+  transaction: Transaction,
+  wallet: PhantomWallet,
+  additionalSigners: Keypair[],
+  skipPreflight: boolean
+): Promise<{ signature: string; result: any }> {
+  if (transaction.feePayer && !transaction.feePayer.equals(wallet.publicKey)) {
+    throw new Error("Transaction fee payer mismatch")
+  }
+  transaction.feePayer = wallet.publicKey
+
+  const signedTransaction = await wallet.signTransaction(transaction)
+  if (additionalSigners.length > 0) {
+    signedTransaction.partialSign(...additionalSigners)
+  }
+
+  // All signers, including any offline nonce authority, must have signed by now
+  if (!signedTransaction.verifySignatures()) {
+    throw new Error("Durable nonce transaction is missing signatures")
+  }
+  validateTransaction(signedTransaction)
+
+  // The fee payer's signature is the transaction id, so it's known before sending
+  const signature = bs58.encode(signedTransaction.signature as Buffer)
+  const { noncePubkey } = SystemInstruction.decodeNonceAdvance(signedTransaction.instructions[0])
+  const nonceValue = signedTransaction.recentBlockhash as string
+  const rawTransaction = signedTransaction.serialize()
+  const minContextSlot = await connection.getSlot('confirmed')
+
+  for (let attempt = 0; attempt < MAX_RETRIES; attempt++) {
+    try {
+      await connection.sendRawTransaction(rawTransaction, {
+        skipPreflight,
+        preflightCommitment: 'confirmed',
+        maxRetries: 3,
+      })
+      break
+    } catch (error) {
+      if (error instanceof SendTransactionError) {
+        // Preflight failures are deterministic, so don't resend. They are only benign when
+        // an earlier attempt already landed, e.g. its response was lost.
+        const transactionLogs = await getTransactionLogs(connection, error)
+        const { value: status } = await connection.getSignatureStatus(signature, {
+          searchTransactionHistory: true,
+        })
+        if (!status) {
+          logger.error("Durable nonce transaction failed", error as Error, { signature, transactionLogs })
+          throw error
+        }
+        break
+      }
+
+      logger.warn(`Durable nonce send attempt ${attempt + 1} failed`, {
+        error: (error as Error).message,
+        signature,
+        attempt: attempt + 1,
+        maxRetries: MAX_RETRIES,
+      })
+
+      if (attempt < MAX_RETRIES - 1) {
+        await sleep(1000 * Math.pow(2, attempt))
+      }
+    }
+  }
+
+  // Confirm even if every send errored: any of them may have reached the cluster.
+  // The nonce strategy resolves once the nonce advances, so bound it with a timeout.
+  const abortController = new AbortController()
+  const timeout = setTimeout(() => abortController.abort(), CONFIRMATION_TIMEOUT)
+
+  try {
+    const confirmation = await connection.confirmTransaction({
+      signature,
+      nonceAccountPubkey: noncePubkey,
+      nonceValue,
+      minContextSlot,
+      abortSignal: abortController.signal,
+    }, 'confirmed')
+
+    if (confirmation.value.err) {
+      throw new Error(`Transaction failed: ${JSON.stringify(confirmation.value.err)}`)
+    }
+
+    return { signature, result: confirmation.value }
+  } finally {
+    clearTimeout(timeout)
+  }
+}
+
 async function confirmTransaction(
   connection: Connection,
   signature: string,